@@ -7,6 +7,7 @@ use std::{
 use anyhow::Result;
 use bytes::Bytes;
 use hyper::{
+  body::HttpBody,
   header::{HeaderName, HeaderValue},
   Body, StatusCode,
 };
@@ -154,14 +155,44 @@ pub struct BlueboatRequest {
   pub body: Vec<u8>,
 }
 
+#[derive(Error, Debug)]
+#[error("request body too large")]
+pub struct RequestBodyTooLarge;
+
 impl BlueboatRequest {
-  pub async fn from_hyper(that: hyper::Request<Body>) -> Result<Self> {
+  /// Reads the request body chunk by chunk, failing with `RequestBodyTooLarge` as soon as
+  /// `max_body_size` is exceeded.
+  pub async fn from_hyper(
+    that: hyper::Request<Body>,
+    max_body_size: Option<usize>,
+  ) -> Result<Self> {
     let headers = decode_hyper_header_map(that.headers());
+    let method = that.method().to_string();
+    let uri = that.uri().to_string();
+    let mut body = that.into_body();
+    let mut full_body: Vec<u8> = vec![];
+
+    if let Some(limit) = max_body_size {
+      if body.size_hint().lower() > limit as u64 {
+        return Err(RequestBodyTooLarge.into());
+      }
+    }
+
+    while let Some(chunk) = body.data().await {
+      let chunk = chunk?;
+      if let Some(limit) = max_body_size {
+        if full_body.len() + chunk.len() > limit {
+          return Err(RequestBodyTooLarge.into());
+        }
+      }
+      full_body.extend_from_slice(&chunk);
+    }
+
     Ok(Self {
-      method: that.method().to_string(),
-      uri: that.uri().to_string(),
+      method,
+      uri,
       headers,
-      body: hyper::body::to_bytes(that.into_body()).await?.to_vec(),
+      body: full_body,
     })
   }
   pub fn from_hyper_no_body<T>(that: &hyper::Request<T>) -> Result<Self> {
@@ -247,3 +278,72 @@ fn encode_hyper_header_map(m: &mut hyper::HeaderMap, source: &HashMap<String, Ve
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use hyper::{body::HttpBody, Body};
+
+  use super::{BlueboatRequest, RequestBodyTooLarge};
+
+  fn request(body: Body) -> hyper::Request<Body> {
+    hyper::Request::post("http://localhost/")
+      .body(body)
+      .unwrap()
+  }
+
+  fn chunked(chunks: Vec<std::io::Result<Bytes>>) -> Body {
+    Body::wrap_stream(futures::stream::iter(chunks))
+  }
+
+  #[tokio::test]
+  async fn test_from_hyper_rejects_declared_length() {
+    // A body of known length reports it through `size_hint`, as a `Content-Length` body does.
+    let body = Body::from(vec![0u8; 11]);
+    assert_eq!(body.size_hint().exact(), Some(11));
+    let e = BlueboatRequest::from_hyper(request(body), Some(10))
+      .await
+      .unwrap_err();
+    assert!(e.is::<RequestBodyTooLarge>());
+  }
+
+  #[tokio::test]
+  async fn test_from_hyper_rejects_chunked_body_mid_stream() {
+    // The third chunk is never read, because the limit is already crossed by the second one.
+    let body = chunked(vec![
+      Ok(Bytes::from(vec![0u8; 6])),
+      Ok(Bytes::from(vec![0u8; 6])),
+      Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "read past limit",
+      )),
+    ]);
+    assert_eq!(body.size_hint().lower(), 0);
+    let e = BlueboatRequest::from_hyper(request(body), Some(10))
+      .await
+      .unwrap_err();
+    assert!(e.is::<RequestBodyTooLarge>());
+  }
+
+  #[tokio::test]
+  async fn test_from_hyper_accepts_body_at_limit() {
+    let req = BlueboatRequest::from_hyper(request(Body::from(vec![1u8; 10])), Some(10))
+      .await
+      .unwrap();
+    assert_eq!(req.body, vec![1u8; 10]);
+
+    let body = chunked(vec![
+      Ok(Bytes::from(vec![1u8; 5])),
+      Ok(Bytes::from(vec![2u8; 5])),
+    ]);
+    let req = BlueboatRequest::from_hyper(request(body), Some(10))
+      .await
+      .unwrap();
+    assert_eq!(req.body.len(), 10);
+
+    let req = BlueboatRequest::from_hyper(request(Body::from(vec![0u8; 11])), None)
+      .await
+      .unwrap();
+    assert_eq!(req.body.len(), 11);
+  }
+}
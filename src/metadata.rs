@@ -1,11 +1,23 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path, str::FromStr};
 
+use anyhow::Result;
 use base64_serde::base64_serde_type;
+use hyper::{
+  header::{HeaderMap, HeaderName, HeaderValue},
+  Method,
+};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 base64_serde_type!(Base64Standard, base64::STANDARD);
 
-#[derive(Serialize, Deserialize, Clone)]
+lazy_static! {
+  static ref ENV_VAR_MATCHER: Regex = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct Metadata {
   #[serde(skip)]
   pub path: String,
@@ -14,6 +26,10 @@ pub struct Metadata {
   pub package: String,
   pub env: HashMap<String, String>,
 
+  /// Hex-encoded SHA-256 digest of the package. Checked after fetching if present.
+  #[serde(default)]
+  pub package_sha256: Option<String>,
+
   #[serde(default)]
   pub mysql: HashMap<String, MysqlMetadata>,
 
@@ -25,9 +41,176 @@ pub struct Metadata {
 
   #[serde(default)]
   pub pubsub: HashMap<String, PubsubMetadata>,
+
+  /// Headers set on every response, overriding values set by the app.
+  #[serde(default)]
+  pub response_headers: HashMap<String, String>,
+
+  #[serde(skip)]
+  pub response_header_values: Vec<(HeaderName, HeaderValue)>,
+
+  /// Headers removed from every request before it is passed to the app.
+  #[serde(default)]
+  pub strip_request_headers: Vec<String>,
+
+  /// Headers set on every request before it is passed to the app, after
+  /// `strip_request_headers` is applied.
+  #[serde(default)]
+  pub request_headers: HashMap<String, String>,
+
+  #[serde(skip)]
+  pub request_header_values: Vec<(HeaderName, HeaderValue)>,
+
+  /// Replaces the `Host` header of every request before it is passed to the app.
+  #[serde(default)]
+  pub rewrite_host: Option<String>,
+
+  #[serde(skip)]
+  pub rewrite_host_value: Option<HeaderValue>,
+
+  /// ISO 3166 country codes allowed to access the app. Requests from other or unknown
+  /// countries are rejected with 403. Metadata that sets this fails to load without
+  /// `--mmdb-city`.
+  #[serde(default)]
+  pub geo_restrict: Option<Vec<String>>,
+
+  /// Answers `OPTIONS` requests with an empty 200 response instead of passing them to the app.
+  /// Preflight headers such as `Access-Control-Allow-Origin` come from `response_headers`.
+  #[serde(default)]
+  pub handle_options_locally: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+impl Metadata {
+  /// Parses metadata as YAML if `path` ends with `.yaml` or `.yml`, and as JSON otherwise.
+  pub fn parse(path: &str, data: &[u8]) -> Result<Self> {
+    if is_yaml(path) {
+      Ok(serde_yaml::from_slice(data)?)
+    } else {
+      Ok(serde_json::from_slice(data)?)
+    }
+  }
+
+  /// Like [`Metadata::parse`], but replaces `${NAME}` placeholders in string values with the
+  /// values of the corresponding environment variables. Expansion happens after parsing, so
+  /// a variable's value is never interpreted as JSON or YAML. Fails if any referenced variable
+  /// is not set.
+  pub fn parse_with_env(path: &str, data: &[u8]) -> Result<Self> {
+    let mut value: serde_yaml::Value = if is_yaml(path) {
+      serde_yaml::from_slice(data)?
+    } else {
+      serde_json::from_slice(data)?
+    };
+    let mut missing: Vec<String> = vec![];
+    expand_env_vars(&mut value, &mut missing);
+    if !missing.is_empty() {
+      anyhow::bail!("missing environment variables: {}", missing.join(", "));
+    }
+
+    // Go back through `parse` so that plain YAML scalars like `8080` still load into `String`
+    // fields. The serializer quotes expanded values, so they cannot change the structure.
+    let data = if is_yaml(path) {
+      serde_yaml::to_vec(&value)?
+    } else {
+      serde_json::to_vec(&value)?
+    };
+    Self::parse(path, &data)
+  }
+
+  /// Whether a client from `country` may access the app. Unknown countries are only allowed if
+  /// `geo_restrict` is not set.
+  pub fn geo_allowed(&self, country: Option<&str>) -> bool {
+    match &self.geo_restrict {
+      Some(allowed) => country
+        .map(|c| allowed.iter().any(|x| x.eq_ignore_ascii_case(c)))
+        .unwrap_or(false),
+      None => true,
+    }
+  }
+
+  /// Whether a request with `method` is answered by Blueboat without invoking the app.
+  pub fn answers_locally(&self, method: &Method) -> bool {
+    self.handle_options_locally && method == Method::OPTIONS
+  }
+
+  /// Applies `strip_request_headers`, `request_headers` and `rewrite_host` to the headers of an
+  /// incoming request.
+  pub fn apply_request_headers(&self, headers: &mut HeaderMap) {
+    for name in &self.strip_request_headers {
+      headers.remove(name.as_str());
+    }
+    for (k, v) in &self.request_header_values {
+      headers.insert(k.clone(), v.clone());
+    }
+    if let Some(host) = &self.rewrite_host_value {
+      headers.insert(hyper::header::HOST, host.clone());
+    }
+  }
+
+  /// Converts header settings into their hyper types. Called once when metadata is loaded, so
+  /// that a malformed entry fails the load instead of every request.
+  pub fn prepare_headers(&mut self) -> Result<()> {
+    self.request_header_values = parse_header_map(&self.request_headers, "request")?;
+    self.response_header_values = parse_header_map(&self.response_headers, "response")?;
+    self.rewrite_host_value = self
+      .rewrite_host
+      .as_ref()
+      .map(|x| HeaderValue::from_str(x).map_err(|_| anyhow::anyhow!("invalid rewrite_host: {}", x)))
+      .transpose()?;
+    Ok(())
+  }
+}
+
+fn parse_header_map(
+  headers: &HashMap<String, String>,
+  kind: &str,
+) -> Result<Vec<(HeaderName, HeaderValue)>> {
+  headers
+    .iter()
+    .map(|(k, v)| {
+      let name = HeaderName::from_str(k)
+        .map_err(|_| anyhow::anyhow!("invalid {} header name: {}", kind, k))?;
+      let value = HeaderValue::from_str(v)
+        .map_err(|_| anyhow::anyhow!("invalid value for {} header '{}'", kind, k))?;
+      Ok((name, value))
+    })
+    .collect()
+}
+
+fn is_yaml(path: &str) -> bool {
+  matches!(
+    Path::new(path).extension().and_then(|x| x.to_str()),
+    Some("yaml") | Some("yml")
+  )
+}
+
+fn expand_env_vars(value: &mut serde_yaml::Value, missing: &mut Vec<String>) {
+  match value {
+    serde_yaml::Value::String(x) => {
+      let expanded = ENV_VAR_MATCHER
+        .replace_all(x, |caps: &Captures| {
+          std::env::var(&caps[1]).unwrap_or_else(|_| {
+            missing.push(caps[1].to_string());
+            String::new()
+          })
+        })
+        .into_owned();
+      *x = expanded;
+    }
+    serde_yaml::Value::Sequence(xs) => {
+      for x in xs {
+        expand_env_vars(x, missing);
+      }
+    }
+    serde_yaml::Value::Mapping(xs) => {
+      for (_, x) in xs.iter_mut() {
+        expand_env_vars(x, missing);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct PubsubMetadata {
   /// Must be a hex-encoded [u8; 16].
   pub namespace: String,
@@ -36,7 +219,7 @@ pub struct PubsubMetadata {
   pub namespace_bytes: [u8; 16],
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct KvNamespaceMetadata {
   pub shard: String,
   pub prefix: String,
@@ -45,21 +228,22 @@ pub struct KvNamespaceMetadata {
   pub raw: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct MysqlMetadata {
   pub url: String,
   pub root_certificate: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ApnsMetadata {
   pub endpoint: ApnsEndpointMetadata,
 
   #[serde(with = "Base64Standard")]
+  #[schemars(with = "String")]
   pub cert: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub enum ApnsEndpointMetadata {
   #[serde(rename = "production")]
   Production,
@@ -67,3 +251,234 @@ pub enum ApnsEndpointMetadata {
   #[serde(rename = "sandbox")]
   Sandbox,
 }
+
+#[cfg(test)]
+mod tests {
+  use hyper::{
+    header::{HeaderMap, HeaderValue},
+    Method,
+  };
+
+  use super::Metadata;
+
+  #[test]
+  fn test_default() {
+    let md = Metadata::default();
+    assert!(md.version.is_empty());
+    assert!(md.package.is_empty());
+    assert!(md.env.is_empty());
+    assert_eq!(md.package_sha256, None);
+    assert!(md.response_headers.is_empty());
+    assert!(md.strip_request_headers.is_empty());
+    assert_eq!(md.rewrite_host, None);
+    assert_eq!(md.geo_restrict, None);
+    assert!(!md.handle_options_locally);
+  }
+
+  #[test]
+  fn test_parse() {
+    let json = r#"{"version": "1", "package": "app.tar", "env": {"A": "b"}}"#;
+    let yaml = "version: \"1\"\npackage: app.tar\nenv:\n  A: b\n";
+    for (path, data) in [("app.json", json), ("app.yaml", yaml), ("app.yml", yaml)] {
+      let md = Metadata::parse(path, data.as_bytes()).unwrap();
+      assert_eq!(md.version, "1");
+      assert_eq!(md.package, "app.tar");
+      assert_eq!(md.env["A"], "b");
+    }
+
+    // The format comes from the extension, and anything other than YAML is JSON.
+    assert!(Metadata::parse("app.json", yaml.as_bytes()).is_err());
+    assert!(Metadata::parse("app", yaml.as_bytes()).is_err());
+  }
+
+  #[test]
+  fn test_response_headers() {
+    let mut md = Metadata::parse(
+      "app.json",
+      br#"{"version": "1", "package": "app.tar", "env": {}}"#,
+    )
+    .unwrap();
+    assert!(md.response_headers.is_empty());
+    md.prepare_headers().unwrap();
+    assert!(md.response_header_values.is_empty());
+
+    let mut md = Metadata::parse(
+      "app.json",
+      br#"{"version": "1", "package": "app.tar", "env": {},
+        "response_headers": {"X-Frame-Options": "DENY"}}"#,
+    )
+    .unwrap();
+    md.prepare_headers().unwrap();
+    assert_eq!(md.response_header_values.len(), 1);
+    assert_eq!(md.response_header_values[0].0, "x-frame-options");
+    assert_eq!(md.response_header_values[0].1, "DENY");
+
+    md.response_headers
+      .insert("bad header".to_string(), "x".to_string());
+    assert!(md.prepare_headers().is_err());
+
+    md.response_headers.remove("bad header");
+    md.response_headers
+      .insert("x-ok".to_string(), "bad\nvalue".to_string());
+    assert!(md.prepare_headers().is_err());
+  }
+
+  #[test]
+  fn test_request_headers() {
+    let mut md = Metadata {
+      strip_request_headers: vec!["X-Forwarded-User".to_string()],
+      request_headers: [("X-Forwarded-User".to_string(), "proxy".to_string())]
+        .into_iter()
+        .collect(),
+      ..Default::default()
+    };
+    md.prepare_headers().unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-user", HeaderValue::from_static("client"));
+    md.apply_request_headers(&mut headers);
+    assert_eq!(headers.get("x-forwarded-user").unwrap(), "proxy");
+
+    md.request_headers
+      .insert("bad header".to_string(), "x".to_string());
+    let e = md.prepare_headers().unwrap_err();
+    assert!(e.to_string().contains("invalid request header name"));
+  }
+
+  #[test]
+  fn test_rewrite_host() {
+    let mut md = Metadata::parse(
+      "app.json",
+      br#"{"version": "1", "package": "app.tar", "env": {}}"#,
+    )
+    .unwrap();
+    assert_eq!(md.rewrite_host, None);
+    md.prepare_headers().unwrap();
+    assert_eq!(md.rewrite_host_value, None);
+
+    let mut md = Metadata::parse(
+      "app.json",
+      br#"{"version": "1", "package": "app.tar", "env": {},
+        "rewrite_host": "internal.example.com"}"#,
+    )
+    .unwrap();
+    md.prepare_headers().unwrap();
+    assert_eq!(
+      md.rewrite_host_value.as_ref().unwrap(),
+      "internal.example.com"
+    );
+
+    md.rewrite_host = Some("bad\nhost".to_string());
+    assert!(md.prepare_headers().is_err());
+  }
+
+  #[test]
+  fn test_strip_request_headers() {
+    let md = Metadata {
+      strip_request_headers: vec!["X-Internal-Token".to_string(), "bad header".to_string()],
+      ..Default::default()
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("x-internal-token", HeaderValue::from_static("secret"));
+    headers.insert("x-other", HeaderValue::from_static("kept"));
+    md.apply_request_headers(&mut headers);
+    assert!(headers.get("x-internal-token").is_none());
+    assert_eq!(headers.get("x-other").unwrap(), "kept");
+    assert_eq!(headers.len(), 1);
+  }
+
+  #[test]
+  fn test_geo_allowed() {
+    let mut md = Metadata::default();
+    assert!(md.geo_allowed(None));
+    assert!(md.geo_allowed(Some("US")));
+
+    md.geo_restrict = Some(vec!["US".to_string(), "ca".to_string()]);
+    assert!(md.geo_allowed(Some("US")));
+    assert!(md.geo_allowed(Some("us")));
+    assert!(md.geo_allowed(Some("CA")));
+    assert!(!md.geo_allowed(Some("DE")));
+    assert!(!md.geo_allowed(None));
+
+    md.geo_restrict = Some(vec![]);
+    assert!(!md.geo_allowed(Some("US")));
+  }
+
+  #[test]
+  fn test_answers_locally() {
+    let mut md = Metadata::default();
+    assert!(!md.answers_locally(&Method::OPTIONS));
+
+    md.handle_options_locally = true;
+    assert!(md.answers_locally(&Method::OPTIONS));
+    assert!(!md.answers_locally(&Method::GET));
+    assert!(!md.answers_locally(&Method::POST));
+  }
+
+  #[test]
+  fn test_expand_env_vars() {
+    std::env::set_var("BLUEBOAT_TEST_EXPAND_A", "hello");
+    std::env::set_var("BLUEBOAT_TEST_EXPAND_B", "world");
+    for (path, data) in [
+      (
+        "app.json",
+        &br#"{"version": "1", "package": "app.tar", "env": {
+          "GREETING": "${BLUEBOAT_TEST_EXPAND_A}, ${BLUEBOAT_TEST_EXPAND_B}!",
+          "LITERAL": "$HOME {x} $"}}"#[..],
+      ),
+      (
+        "app.yaml",
+        &b"version: \"1\"\npackage: app.tar\nenv:\n  GREETING: \"${BLUEBOAT_TEST_EXPAND_A}, ${BLUEBOAT_TEST_EXPAND_B}!\"\n  LITERAL: \"$HOME {x} $\"\n"[..],
+      ),
+    ] {
+      let md = Metadata::parse_with_env(path, data).unwrap();
+      assert_eq!(md.env["GREETING"], "hello, world!");
+      assert_eq!(md.env["LITERAL"], "$HOME {x} $");
+    }
+  }
+
+  #[test]
+  fn test_expand_env_vars_escaping() {
+    let value = "x\", \"package\": \"other.tar\nsecond line";
+    std::env::set_var("BLUEBOAT_TEST_EXPAND_QUOTED", value);
+    for (path, data) in [
+      (
+        "app.json",
+        &br#"{"version": "1", "package": "app.tar", "env": {"X": "${BLUEBOAT_TEST_EXPAND_QUOTED}"}}"#[..],
+      ),
+      (
+        "app.yaml",
+        &b"version: \"1\"\npackage: app.tar\nenv:\n  X: ${BLUEBOAT_TEST_EXPAND_QUOTED}\n"[..],
+      ),
+    ] {
+      let md = Metadata::parse_with_env(path, data).unwrap();
+      assert_eq!(md.package, "app.tar");
+      assert_eq!(md.env["X"], value);
+    }
+  }
+
+  #[test]
+  fn test_expand_env_vars_plain_scalars() {
+    std::env::set_var("BLUEBOAT_TEST_EXPAND_HOST", "db.internal");
+    let md = Metadata::parse_with_env(
+      "app.yaml",
+      b"version: 1\npackage: app.tar\nenv:\n  PORT: 8080\n  DEBUG: true\n  HOST: ${BLUEBOAT_TEST_EXPAND_HOST}\nhandle_options_locally: true\n",
+    )
+    .unwrap();
+    assert_eq!(md.version, "1");
+    assert_eq!(md.env["PORT"], "8080");
+    assert_eq!(md.env["DEBUG"], "true");
+    assert_eq!(md.env["HOST"], "db.internal");
+    assert!(md.handle_options_locally);
+  }
+
+  #[test]
+  fn test_expand_env_vars_missing() {
+    std::env::remove_var("BLUEBOAT_TEST_EXPAND_MISSING");
+    let e = Metadata::parse_with_env(
+      "app.json",
+      br#"{"version": "1", "package": "app.tar", "env": {"X": "${BLUEBOAT_TEST_EXPAND_MISSING}"}}"#,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("BLUEBOAT_TEST_EXPAND_MISSING"));
+  }
+}
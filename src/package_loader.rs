@@ -3,33 +3,40 @@ use std::time::Duration;
 use crate::{
   metadata::Metadata,
   package::PackageKey,
-  server::{cache, tenancy, Tenancy},
+  server::{cache, max_package_size, package_fetch_retries, tenancy, Tenancy},
 };
 use anyhow::Result;
-use rusoto_s3::{GetObjectRequest, S3};
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
 use tokio::io::AsyncReadExt;
 
 const PACKAGE_TTL: Duration = Duration::from_secs(86400 * 30);
+const FETCH_RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
 
 pub async fn load_package(pk: &PackageKey, md: &Metadata) -> Result<Vec<u8>> {
   if let Tenancy::SingleTenant { package, .. } = tenancy() {
     return Ok(package.clone());
   }
 
+  // Cached entries are keyed by version only and may predate `package_sha256`, so every
+  // path is verified, not just fresh fetches.
   let cache = cache();
   let value = cache.get(&pk.version)?;
   if let Some(x) = value {
+    verify_package_digest(md, &x.data)?;
     return Ok(x.data);
   }
 
   let (upd, value) = cache.get_for_update(&pk.version).await?;
   if let Some(x) = value {
+    verify_package_digest(md, &x.data)?;
     return Ok(x.data);
   }
 
   let new_data = fetch_package(md)
     .await
     .map_err(|e| e.context("failed to fetch package"))?;
+  verify_package_digest(md, &new_data)?;
   upd.write(&new_data, PACKAGE_TTL)?;
   Ok(new_data)
 }
@@ -39,19 +46,108 @@ async fn fetch_package(md: &Metadata) -> Result<Vec<u8>> {
     Tenancy::MultiTenant { s3 } => s3,
     _ => panic!("fetch_package called in single-tenant mode"),
   };
+  let retries = package_fetch_retries();
+  let mut attempt = 0u32;
+  loop {
+    match fetch_package_once(s3c, bucket, md).await {
+      Ok(x) => return Ok(x),
+      Err(e) if attempt < retries && is_transient(&e) => {
+        tracing::warn!(package = %md.package, attempt, error = %e, "package fetch failed, retrying");
+        tokio::time::sleep(FETCH_RETRY_BASE_DELAY * 2u32.pow(attempt.min(10))).await;
+        attempt += 1;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+async fn fetch_package_once(s3c: &S3Client, bucket: &str, md: &Metadata) -> Result<Vec<u8>> {
   let output = s3c
     .get_object(GetObjectRequest {
-      bucket: bucket.clone(),
+      bucket: bucket.to_string(),
       key: md.package.clone(),
       ..Default::default()
     })
     .await?;
+  let limit = max_package_size();
+  if let (Some(limit), Some(len)) = (limit, output.content_length) {
+    if len > limit as i64 {
+      anyhow::bail!("package too large: {} bytes", len);
+    }
+  }
   let mut body: Vec<u8> = vec![];
-  output
+  let mut reader = output
     .body
     .ok_or_else(|| anyhow::anyhow!("missing package for this metadata"))?
-    .into_async_read()
-    .read_to_end(&mut body)
-    .await?;
+    .into_async_read();
+  if let Some(limit) = limit {
+    // Read one byte past the limit so that an oversized package can be detected.
+    reader.take(limit as u64 + 1).read_to_end(&mut body).await?;
+    if body.len() > limit {
+      anyhow::bail!("package too large: more than {} bytes", limit);
+    }
+  } else {
+    reader.read_to_end(&mut body).await?;
+  }
   Ok(body)
 }
+
+/// Connection failures, 5xx responses and errors while streaming the body are worth retrying.
+fn is_transient(e: &anyhow::Error) -> bool {
+  if let Some(e) = e.downcast_ref::<RusotoError<GetObjectError>>() {
+    return match e {
+      RusotoError::HttpDispatch(_) => true,
+      RusotoError::Unknown(res) => res.status.is_server_error(),
+      _ => false,
+    };
+  }
+  e.is::<std::io::Error>()
+}
+
+pub fn verify_package_digest(md: &Metadata, package: &[u8]) -> Result<()> {
+  if let Some(expected) = &md.package_sha256 {
+    let actual = hex::encode(ring::digest::digest(&ring::digest::SHA256, package));
+    if !actual.eq_ignore_ascii_case(expected) {
+      tracing::warn!(package = %md.package, expected = %expected, actual = %actual, "package digest mismatch");
+      anyhow::bail!("package digest mismatch");
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::verify_package_digest;
+  use crate::metadata::Metadata;
+
+  const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+  fn md_with_digest(digest: Option<&str>) -> Metadata {
+    Metadata {
+      package: "app.tar".to_string(),
+      package_sha256: digest.map(|x| x.to_string()),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn test_verify_package_digest() {
+    // No digest configured
+    assert!(verify_package_digest(&md_with_digest(None), b"hello").is_ok());
+
+    // Match
+    assert!(verify_package_digest(&md_with_digest(Some(HELLO_SHA256)), b"hello").is_ok());
+
+    // Match with mixed-case hex
+    let mixed = format!(
+      "{}{}",
+      HELLO_SHA256[..32].to_ascii_uppercase(),
+      &HELLO_SHA256[32..]
+    );
+    assert!(verify_package_digest(&md_with_digest(Some(&mixed)), b"hello").is_ok());
+
+    // Mismatch
+    assert!(verify_package_digest(&md_with_digest(Some(HELLO_SHA256)), b"hello!").is_err());
+    assert!(verify_package_digest(&md_with_digest(Some("")), b"hello").is_err());
+  }
+}
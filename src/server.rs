@@ -11,11 +11,12 @@ use crate::headers::{
   HDR_REQ_CLIENT_SUBDIVISION_PREFIX, HDR_REQ_CLIENT_WPBL, HDR_REQ_METADATA, HDR_REQ_REQUEST_ID,
   HDR_RES_HANDLE_LATENCY, HDR_RES_REQUEST_ID, PROXY_HEADER_WHITELIST,
 };
-use crate::ipc::{BlueboatIpcReqV, BlueboatIpcRes};
+use crate::ipc::{BlueboatIpcReqV, BlueboatIpcRes, RequestBodyTooLarge};
 use crate::logsvc::LogService;
 use crate::lpch::{BackgroundEntry, LowPriorityMsg};
 use crate::mds::config_v2::MdsConfig;
 use crate::mds::{MdsService, MDS};
+use crate::package_loader::verify_package_digest;
 use crate::pm::pm_handle;
 use crate::pubsub::mq::{MessageQueue, MessageQueueConfig};
 use crate::pubsub::MQ;
@@ -59,9 +60,9 @@ use uuid::Uuid;
   about = "The monolithic runtime for modern web backends."
 )]
 struct Opt {
-  /// Listen address.
-  #[structopt(short, long)]
-  listen: SocketAddr,
+  /// Listen address. Can be specified multiple times to listen on more than one address.
+  #[structopt(short, long, required_unless = "print_schema", number_of_values = 1)]
+  listen: Vec<SocketAddr>,
 
   /// S3 bucket for storing apps' code and metadata.
   #[structopt(long, default_value = "-")]
@@ -111,6 +112,18 @@ struct Opt {
   #[structopt(long, default_value = "200")]
   md_cache_size: u64,
 
+  /// Max request body size. Requests with a larger body are rejected with 413. Unlimited by default.
+  #[structopt(long)]
+  max_request_body_size_kb: Option<u64>,
+
+  /// Max size of an app package fetched from S3. Larger packages fail to load. Unlimited by default.
+  #[structopt(long)]
+  max_package_size_kb: Option<u64>,
+
+  /// How many times a failed package download from S3 is retried, with exponential backoff.
+  #[structopt(long, default_value = "3")]
+  package_fetch_retries: u32,
+
   /// Whether this instance should listen to and process background tasks.
   #[structopt(long)]
   accept_background_tasks: bool,
@@ -138,6 +151,10 @@ struct Opt {
   /// Run in single-tenant mode with the provided `metadata.json`.
   #[structopt(long, default_value = "-")]
   single_tenant: String,
+
+  /// Print the JSON schema of app metadata to stdout and exit.
+  #[structopt(long)]
+  print_schema: bool,
 }
 
 struct LpContext {
@@ -154,6 +171,9 @@ static CACHE: OnceCell<sqlite_cache::Topic> = OnceCell::const_new();
 static MD_CACHE: OnceCell<MdCacheType> = OnceCell::const_new();
 static MEM_HIGH_WATERMARK_KB: OnceCell<u64> = OnceCell::const_new();
 static MEM_CRITICAL_WATERMARK_KB: OnceCell<u64> = OnceCell::const_new();
+static MAX_REQUEST_BODY_SIZE: OnceCell<Option<usize>> = OnceCell::const_new();
+static MAX_PACKAGE_SIZE: OnceCell<Option<usize>> = OnceCell::const_new();
+static PACKAGE_FETCH_RETRIES: OnceCell<u32> = OnceCell::const_new();
 static LP_TX: OnceCell<Mutex<IpcSender<LowPriorityMsg>>> = OnceCell::const_new();
 static MMDB_CITY: OnceCell<Option<maxminddb::Reader<Mmap>>> = OnceCell::const_new();
 static WPBL_DB: OnceCell<Option<WpblDb>> = OnceCell::const_new();
@@ -201,6 +221,14 @@ fn md_cache() -> &'static MdCacheType {
   MD_CACHE.get().unwrap()
 }
 
+pub fn max_package_size() -> Option<usize> {
+  *MAX_PACKAGE_SIZE.get().unwrap()
+}
+
+pub fn package_fetch_retries() -> u32 {
+  *PACKAGE_FETCH_RETRIES.get().unwrap()
+}
+
 #[derive(Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
 enum MemoryWatermark {
   Normal,
@@ -292,6 +320,12 @@ impl MemoryWatermark {
 async fn async_main() {
   let opt = Opt::from_args();
 
+  if opt.print_schema {
+    let schema = schemars::schema_for!(Metadata);
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+    return;
+  }
+
   let mut syslog_service: Option<LogService> = None;
 
   if opt.syslog_kafka != "-" {
@@ -348,16 +382,18 @@ async fn async_main() {
       .unwrap_or_else(|_| unreachable!());
     tracing::info!("Running in multi-tenant mode.");
   } else {
-    let metadata_json =
-      std::fs::read_to_string(&opt.single_tenant).expect("failed to read single-tenant metadata");
-    let metadata: Metadata =
-      serde_json::from_str(&metadata_json).expect("failed to parse single-tenant metadata");
+    let metadata_data =
+      std::fs::read(&opt.single_tenant).expect("failed to read single-tenant metadata");
+    // Only trusted local metadata may reference the server's own environment.
+    let metadata = Metadata::parse_with_env(&opt.single_tenant, &metadata_data)
+      .expect("failed to parse single-tenant metadata");
     let package_path = Path::new(&opt.single_tenant)
       .parent()
       .expect("failed to get metadata directory")
       .to_path_buf()
       .join(&metadata.package);
     let package = std::fs::read(&package_path).expect("failed to read package");
+    verify_package_digest(&metadata, &package).expect("failed to verify package");
     TENANCY
       .set(Tenancy::SingleTenant { metadata, package })
       .unwrap_or_else(|_| unreachable!());
@@ -405,6 +441,23 @@ async fn async_main() {
   MEM_CRITICAL_WATERMARK_KB
     .set(opt.mem_critical_watermark_kb)
     .unwrap_or_else(|_| unreachable!());
+  MAX_REQUEST_BODY_SIZE
+    .set(
+      opt
+        .max_request_body_size_kb
+        .map(|x| (x as usize).saturating_mul(1024)),
+    )
+    .unwrap_or_else(|_| unreachable!());
+  MAX_PACKAGE_SIZE
+    .set(
+      opt
+        .max_package_size_kb
+        .map(|x| (x as usize).saturating_mul(1024)),
+    )
+    .unwrap_or_else(|_| unreachable!());
+  PACKAGE_FETCH_RETRIES
+    .set(opt.package_fetch_retries)
+    .unwrap_or_else(|_| unreachable!());
 
   let (lp_tx, lp_rx) = smr::ipc_channel::ipc::channel::<LowPriorityMsg>().unwrap();
   LP_TX
@@ -516,17 +569,32 @@ async fn async_main() {
     log::warn!("Background tasks not implemented.");
   }
 
-  let make_svc = make_service_fn(|_| async move { Ok::<_, hyper::Error>(service_fn(handle)) });
-
-  tracing::warn!(address = %opt.listen, "start listener");
-  let server = Server::bind(&opt.listen).serve(make_svc);
-  let graceful = server.with_graceful_shutdown(shutdown_signal());
+  let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+  let servers = opt
+    .listen
+    .iter()
+    .map(|addr| {
+      let make_svc = make_service_fn(|_| async move { Ok::<_, hyper::Error>(service_fn(handle)) });
+      let mut shutdown_rx = shutdown_rx.clone();
+      tracing::warn!(address = %addr, "start listener");
+      Server::bind(addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+          let _ = shutdown_rx.changed().await;
+        })
+    })
+    .collect::<Vec<_>>();
+  tokio::spawn(async move {
+    shutdown_signal().await;
+    let _ = shutdown_tx.send(());
+  });
 
-  if let Err(e) = graceful.await {
-    tracing::error!(error = %e, "server error");
-  } else {
-    tracing::warn!("server shutdown");
+  for res in futures::future::join_all(servers).await {
+    if let Err(e) = res {
+      tracing::error!(error = %e, "server error");
+    }
   }
+  tracing::warn!("server shutdown");
 
   let bg_shutdown_start = Instant::now();
   std::mem::forget(BACKGROUND_TASK_LOCK.write().await);
@@ -692,7 +760,7 @@ async fn load_md(path: &str) -> Result<Arc<Metadata>> {
         .into_async_read()
         .read_to_end(&mut body)
         .await?;
-      serde_json::from_slice(&body)?
+      Metadata::parse(path, &body)?
     }
     Tenancy::SingleTenant { metadata, .. } => metadata.clone(),
   };
@@ -710,6 +778,11 @@ async fn load_md(path: &str) -> Result<Arc<Metadata>> {
       }
     }
   }
+  if md.geo_restrict.is_some() && MMDB_CITY.get().unwrap().is_none() {
+    // Without a GeoIP database no request carries a country, so every request would be denied.
+    anyhow::bail!("geo_restrict requires --mmdb-city");
+  }
+  md.prepare_headers()?;
   Ok(Arc::new(md))
 }
 
@@ -779,19 +852,74 @@ async fn raw_handle(mut req: Request<Body>, md_path: &String) -> Result<Response
   req
     .headers_mut()
     .insert(HDR_REQ_REQUEST_ID, HeaderValue::from_str(&request_id)?);
+  let geo_allowed = md.geo_allowed(
+    req
+      .headers()
+      .get(HDR_REQ_CLIENT_COUNTRY)
+      .and_then(|x| x.to_str().ok()),
+  );
+  md.apply_request_headers(req.headers_mut());
 
-  if req.uri().path() == "/_blueboat/events" {
+  if geo_allowed && req.uri().path() == "/_blueboat/events" {
     return crate::pubsub::sse::handle_sse(&request_id, req, md)
       .await
       .map_err(|e| e.context("sse"));
   }
-  let request = BlueboatRequest::from_hyper(req).await?;
+  let mut res = if !geo_allowed {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::FORBIDDEN;
+    res
+  } else if md.answers_locally(req.method()) {
+    Response::new(Body::empty())
+  } else {
+    invoke_http(req, &md, md_path, &request_id).await?
+  };
+  for (k, v) in &md.response_header_values {
+    res.headers_mut().insert(k.clone(), v.clone());
+  }
+  let handle_dur = handle_start.elapsed();
+  res.headers_mut().insert(
+    HDR_RES_HANDLE_LATENCY,
+    HeaderValue::from_str(&format!("{:.2}", handle_dur.as_secs_f64() * 1000.0)).unwrap(),
+  );
+  if let Ok(v) = HeaderValue::from_str(&request_id) {
+    res.headers_mut().insert(HDR_RES_REQUEST_ID, v);
+  }
+  Ok(res)
+}
+
+async fn invoke_http(
+  req: Request<Body>,
+  md: &Arc<Metadata>,
+  md_path: &str,
+  request_id: &str,
+) -> Result<Response<Body>> {
+  let is_head = req.method() == hyper::Method::HEAD;
+  let max_body_size = *MAX_REQUEST_BODY_SIZE.get().unwrap();
+  let request = match BlueboatRequest::from_hyper(req, max_body_size).await {
+    Ok(x) => x,
+    Err(e) if e.is::<RequestBodyTooLarge>() => {
+      let mut res = Response::new(Body::empty());
+      *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+      return Ok(res);
+    }
+    Err(e) => return Err(e),
+  };
   let request = BlueboatIpcReq {
     v: BlueboatIpcReqV::Http(request),
-    id: request_id.clone(),
+    id: request_id.to_string(),
   };
-  let res = generic_invoke(request, md, None).await;
-  let mut res = match res {
+  let res = generic_invoke(request, md.clone(), None).await;
+  let res = match res {
+    Ok(res) if is_head => {
+      // Responses to HEAD must not carry a body, but should describe the one that would be sent.
+      let mut hyper_res = res.response.into_hyper(bytes::Bytes::new())?;
+      hyper_res.headers_mut().insert(
+        hyper::header::CONTENT_LENGTH,
+        HeaderValue::from(res.body.len()),
+      );
+      hyper_res
+    }
     Ok(res) => res.response.into_hyper(res.body)?,
     Err(e) => {
       let mut res = hyper::Response::new(Body::from("invoke error".to_string()));
@@ -800,14 +928,6 @@ async fn raw_handle(mut req: Request<Body>, md_path: &String) -> Result<Response
       res
     }
   };
-  let handle_dur = handle_start.elapsed();
-  res.headers_mut().insert(
-    HDR_RES_HANDLE_LATENCY,
-    HeaderValue::from_str(&format!("{:.2}", handle_dur.as_secs_f64() * 1000.0)).unwrap(),
-  );
-  if let Ok(v) = HeaderValue::from_str(&request_id) {
-    res.headers_mut().insert(HDR_RES_REQUEST_ID, v);
-  }
   Ok(res)
 }
 